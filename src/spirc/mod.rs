@@ -1,6 +1,9 @@
+use std::time::{Duration, Instant};
+
 use futures::{Async, Poll, Future, Stream, Sink};
+use tokio_core::reactor::Timeout;
 
-use broadcast::BroadcastReceiver;
+use broadcast::{self, BroadcastReceiver, BroadcastSender};
 use connection::ConnectionChange;
 use session::Session;
 use types::*;
@@ -13,6 +16,69 @@ use util::SpotifyId;
 mod command_sender;
 use self::command_sender::CommandSender;
 
+// How long the device waits without any activity before it voluntarily
+// drops off as the active Connect device.
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 5 * 60;
+
+/// Notable state transitions of a `SpircManager`, for embedders that want
+/// to observe what the device is doing without parsing spirc frames
+/// themselves. Obtain a stream of these via `SpircManager::events()`.
+#[derive(Clone, Debug)]
+pub enum SpircEvent {
+    Playing { track: SpotifyId, position_ms: u32 },
+    Paused,
+    Stopped,
+    TrackChanged(SpotifyId),
+    VolumeChanged(u16),
+    Active,
+    Inactive,
+}
+
+/// How the raw 0..=0xFFFF volume carried by spirc `State.volume` is mapped
+/// to the linear gain applied to the mixer.
+#[derive(Clone, Copy, Debug)]
+pub enum VolumeCtrl {
+    /// Gain tracks the raw volume directly; feels wrong at low levels
+    /// since human hearing is roughly logarithmic.
+    Linear,
+    /// Gain follows a logarithmic curve, matching how loudness is
+    /// actually perceived.
+    Logarithmic,
+    /// Gain is always unity: incoming volume changes are still stored and
+    /// reported back, but never attenuate the output.
+    Fixed,
+}
+
+impl VolumeCtrl {
+    // Dynamic range used by the logarithmic curve: how many dB quieter
+    // the lowest non-zero volume is than `volume = 0xFFFF`. `volume = 0`
+    // itself maps to silence.
+    const DYNAMIC_RANGE_DB: f64 = 60.0;
+
+    fn to_gain(&self, volume: u16) -> f64 {
+        let normalized = volume as f64 / 0xFFFF as f64;
+
+        match *self {
+            VolumeCtrl::Linear => normalized,
+            VolumeCtrl::Logarithmic => {
+                if normalized <= 0.0 {
+                    0.0
+                } else {
+                    10f64.powf((normalized - 1.0) * Self::DYNAMIC_RANGE_DB / 20.0)
+                }
+            }
+            VolumeCtrl::Fixed => 1.0,
+        }
+    }
+
+    fn volume_steps(&self) -> i64 {
+        match *self {
+            VolumeCtrl::Fixed => 0,
+            VolumeCtrl::Linear | VolumeCtrl::Logarithmic => 10,
+        }
+    }
+}
+
 pub struct SpircManager {
     ident: String,
 
@@ -25,6 +91,11 @@ pub struct SpircManager {
 
     state: SpircState,
     player: Player,
+    events: BroadcastSender<SpircEvent>,
+    volume_ctrl: VolumeCtrl,
+
+    idle_timeout: Duration,
+    timeout: Timeout,
 }
 
 pub struct SpircState {
@@ -38,6 +109,10 @@ pub struct SpircState {
     index: u32,
     tracks: Vec<SpotifyId>,
 
+    shuffle: bool,
+    repeat: bool,
+    shuffle_order: Vec<u32>,
+
     update_id: i64,
 
     position_ms: u32,
@@ -57,6 +132,10 @@ impl SpircState {
             index: 0,
             tracks: Vec::new(),
 
+            shuffle: false,
+            repeat: false,
+            shuffle_order: Vec::new(),
+
             update_id: 0,
 
             position_ms: 0,
@@ -70,11 +149,103 @@ impl SpircState {
                            .filter(|track| track.has_gid())
                            .map(|track| SpotifyId::from_raw(track.get_gid()))
                            .collect();
+
+        self.shuffle = state.get_shuffle();
+        self.repeat = state.get_repeat();
+        self.reshuffle();
+    }
+
+    // Fisher-Yates, seeded off the update id so a given `Load` always
+    // produces the same order (reshuffling on every TrackEnd would make
+    // "shuffle" indistinguishable from "random skip").
+    fn reshuffle(&mut self) {
+        let len = self.tracks.len() as u32;
+        self.shuffle_order = (0..len).collect();
+
+        if !self.shuffle || len < 2 {
+            return;
+        }
+
+        let mut seed = self.update_id as u64 ^ (len as u64).wrapping_mul(0x9E3779B97F4A7C15);
+        for i in (1..self.shuffle_order.len()).rev() {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            let j = (seed % (i as u64 + 1)) as usize;
+            self.shuffle_order.swap(i, j);
+        }
+    }
+
+    // The track to move to from the current index, honouring `shuffle`
+    // and `repeat`. `None` means playback should stop.
+    //
+    // spirc's `State.repeat` is a single flag with no separate "repeat
+    // just this track" signal, so here it means "repeat the whole
+    // context": running off either end wraps around to the other end
+    // (in shuffle order, when shuffled) instead of reloading the track
+    // that was just playing.
+    fn next_index(&self) -> Option<u32> {
+        let len = self.tracks.len() as u32;
+        if len == 0 {
+            return None;
+        }
+
+        if self.shuffle {
+            let pos = self.shuffle_order.iter().position(|&i| i == self.index).unwrap_or(0);
+            if pos + 1 < self.shuffle_order.len() {
+                Some(self.shuffle_order[pos + 1])
+            } else if self.repeat {
+                Some(self.shuffle_order[0])
+            } else {
+                None
+            }
+        } else if self.index + 1 < len {
+            Some(self.index + 1)
+        } else if self.repeat {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    // The mirror image of `next_index`, used by "previous": walks
+    // `shuffle_order` backwards when shuffled instead of assuming
+    // strict linear order.
+    fn prev_index(&self) -> Option<u32> {
+        let len = self.tracks.len() as u32;
+        if len == 0 {
+            return None;
+        }
+
+        if self.shuffle {
+            let pos = self.shuffle_order.iter().position(|&i| i == self.index).unwrap_or(0);
+            if pos > 0 {
+                Some(self.shuffle_order[pos - 1])
+            } else if self.repeat {
+                Some(self.shuffle_order[self.shuffle_order.len() - 1])
+            } else {
+                None
+            }
+        } else if self.index > 0 {
+            Some(self.index - 1)
+        } else if self.repeat {
+            Some(len - 1)
+        } else {
+            None
+        }
     }
 }
 
 impl SpircManager {
     pub fn new(session: &Session, name: String) -> SpircManager {
+        SpircManager::new_with_volume_ctrl(session, name, VolumeCtrl::Logarithmic)
+    }
+
+    pub fn new_with_volume_ctrl(session: &Session, name: String, volume_ctrl: VolumeCtrl) -> SpircManager {
+        let idle_timeout = Duration::from_secs(DEFAULT_IDLE_TIMEOUT_SECS);
+        let timeout = Timeout::new(idle_timeout, &session.handle())
+            .expect("Unable to create idle timeout");
+
         SpircManager {
             ident: session.device_id(),
 
@@ -87,9 +258,44 @@ impl SpircManager {
 
             state: SpircState::new(name),
             player: Player::new(session.clone()),
+            events: broadcast::channel(),
+            volume_ctrl: volume_ctrl,
+
+            idle_timeout: idle_timeout,
+            timeout: timeout,
         }
     }
 
+    /// Subscribe to the event stream describing this device's state
+    /// transitions (play/pause, track changes, volume, active/inactive).
+    pub fn events(&self) -> BroadcastReceiver<SpircEvent> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, event: SpircEvent) {
+        self.events.send(event);
+    }
+
+    /// Configure how long the device may sit idle (no incoming frames, no
+    /// playback progress) before it voluntarily goes inactive. Headless
+    /// deployments that don't want auto-shutdown can pass a very large
+    /// duration here.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = idle_timeout;
+        self.reset_idle_timer();
+    }
+
+    fn reset_idle_timer(&mut self) {
+        self.timeout.reset(Instant::now() + self.idle_timeout);
+    }
+
+    // Push the stored volume through the configured curve and out to the
+    // mixer; called whenever `self.state.volume` changes.
+    fn apply_volume(&mut self) {
+        let gain = self.volume_ctrl.to_gain(self.state.volume);
+        self.player.set_volume(gain);
+    }
+
     fn build_subscription<'a>(&self, username: String) -> SpStream<'a, Frame> {
         let uri = format!("hm://remote/user/{}", username);
         let ident = self.ident.clone();
@@ -139,6 +345,8 @@ impl SpircManager {
     }
 
     fn process_frame(&mut self, frame: Frame) {
+        self.reset_idle_timer();
+
         if frame.get_state_update_id() > self.state.update_id {
             self.state.update_id = frame.get_state_update_id();
         }
@@ -152,6 +360,7 @@ impl SpircManager {
             self.player.stop();
 
             self.notify(None);
+            self.emit(SpircEvent::Inactive);
         }
 
         let sender = frame.get_ident().to_owned();
@@ -160,7 +369,10 @@ impl SpircManager {
 
             MessageType::kMessageTypeVolume => {
                 self.state.volume = frame.get_volume() as u16;
+                self.apply_volume();
+
                 self.notify(None);
+                self.emit(SpircEvent::VolumeChanged(self.state.volume));
             }
 
             MessageType::kMessageTypeLoad => {
@@ -169,6 +381,7 @@ impl SpircManager {
                 if !self.state.is_active {
                     self.state.is_active = true;
                     self.state.became_active_at = self.session.time() as i64;
+                    self.emit(SpircEvent::Active);
                 }
 
                 self.state.load_tracks(frame.get_state());
@@ -177,15 +390,115 @@ impl SpircManager {
                 if track_index < self.state.tracks.len() {
                     let track_id = self.state.tracks[track_index];
                     self.player.load(track_id);
+                    self.apply_volume();
 
                     self.state.status = PlayStatus::kPlayStatusPlay;
                     self.state.position_ms = 0;
                     self.state.position_measured_at = self.session.time();
+
+                    self.emit(SpircEvent::TrackChanged(track_id));
+                    self.emit(SpircEvent::Playing { track: track_id, position_ms: 0 });
+                }
+
+                self.notify(None);
+            }
+
+            MessageType::kMessageTypePlay => {
+                let index = self.state.index as usize;
+                if let Some(&track) = self.state.tracks.get(index) {
+                    self.state.status = PlayStatus::kPlayStatusPlay;
+                    self.player.play();
+
+                    self.state.position_measured_at = self.session.time();
+
+                    self.emit(SpircEvent::Playing {
+                        track: track,
+                        position_ms: self.state.position_ms,
+                    });
                 }
 
                 self.notify(None);
             }
 
+            MessageType::kMessageTypePause => {
+                if self.state.index as usize < self.state.tracks.len() {
+                    self.state.status = PlayStatus::kPlayStatusPause;
+                    self.player.pause();
+
+                    self.state.position_measured_at = self.session.time();
+                    self.emit(SpircEvent::Paused);
+                }
+
+                self.notify(None);
+            }
+
+            MessageType::kMessageTypeNext => {
+                match self.state.next_index() {
+                    Some(index) => {
+                        self.state.index = index;
+                        let track_id = self.state.tracks[index as usize];
+                        self.player.load(track_id);
+
+                        self.state.position_ms = 0;
+                        self.state.position_measured_at = self.session.time();
+
+                        self.emit(SpircEvent::TrackChanged(track_id));
+                        self.emit(SpircEvent::Playing { track: track_id, position_ms: 0 });
+                    }
+                    None => {
+                        self.state.status = PlayStatus::kPlayStatusStop;
+                        self.player.stop();
+                        self.emit(SpircEvent::Stopped);
+                    }
+                }
+
+                self.notify(None);
+            }
+
+            MessageType::kMessageTypePrev => {
+                match self.state.prev_index() {
+                    Some(index) => {
+                        self.state.index = index;
+                        let track_id = self.state.tracks[index as usize];
+                        self.player.load(track_id);
+
+                        self.state.position_ms = 0;
+                        self.state.position_measured_at = self.session.time();
+
+                        self.emit(SpircEvent::TrackChanged(track_id));
+                        self.emit(SpircEvent::Playing { track: track_id, position_ms: 0 });
+                    }
+                    None => {
+                        self.state.status = PlayStatus::kPlayStatusStop;
+                        self.player.stop();
+                        self.emit(SpircEvent::Stopped);
+                    }
+                }
+
+                self.notify(None);
+            }
+
+            MessageType::kMessageTypeSeek => {
+                self.state.position_ms = frame.get_position();
+                self.state.position_measured_at = self.session.time();
+                self.player.seek(self.state.position_ms);
+
+                self.notify(None);
+            }
+
+            MessageType::kMessageTypeShuffle => {
+                self.state.shuffle = frame.get_state().get_shuffle();
+                self.state.reshuffle();
+
+                self.notify(None);
+            }
+
+            MessageType::kMessageTypeRepeat => {
+                self.state.repeat = frame.get_state().get_repeat();
+
+                self.notify(None);
+            }
+
             _ => (),
         }
     }
@@ -232,6 +545,9 @@ impl SpircManager {
             }).collect(),
 
             playing_from_fallback: true,
+
+            shuffle: self.state.shuffle,
+            repeat: self.state.repeat,
         })
     }
 
@@ -272,7 +588,7 @@ impl SpircManager {
                 },
                 @{
                     typ: protocol::spirc::CapabilityType::kVolumeSteps,
-                    intValue => [10]
+                    intValue => [self.volume_ctrl.volume_steps()]
                 },
                 @{
                     typ: protocol::spirc::CapabilityType::kSupportedContexts,
@@ -324,13 +640,27 @@ impl Future for SpircManager {
 
             match self.player.poll()? {
                 Async::Ready(Some(PlayerEvent::TrackEnd)) => {
-                    self.state.index = (self.state.index + 1) % self.state.tracks.len() as u32;
-                    let track_id = self.state.tracks[self.state.index as usize];
-                    self.player.load(track_id);
+                    match self.state.next_index() {
+                        Some(index) => {
+                            self.state.index = index;
+                            let track_id = self.state.tracks[index as usize];
+                            self.player.load(track_id);
+
+                            self.state.position_ms = 0;
+
+                            self.emit(SpircEvent::TrackChanged(track_id));
+                            self.emit(SpircEvent::Playing { track: track_id, position_ms: 0 });
+                        }
+                        None => {
+                            self.state.status = PlayStatus::kPlayStatusStop;
+                            self.player.stop();
+                            self.emit(SpircEvent::Stopped);
+                        }
+                    }
 
                     self.state.update_id = self.session.time() as i64;
-                    self.state.position_ms = 0;
                     self.state.position_measured_at = self.session.time();
+                    self.reset_idle_timer();
                     self.notify(None);
 
                     progress = true;
@@ -338,13 +668,164 @@ impl Future for SpircManager {
                 Async::Ready(Some(PlayerEvent::Playing(position_ms))) => {
                     self.state.position_ms = position_ms;
                     self.state.position_measured_at = self.session.time();
+                    self.reset_idle_timer();
+
+                    let index = self.state.index as usize;
+                    if let Some(&track) = self.state.tracks.get(index) {
+                        self.emit(SpircEvent::Playing {
+                            track: track,
+                            position_ms: position_ms,
+                        });
+                    }
                 }
                 _ => (),
             }
 
+            if let Async::Ready(_) = self.timeout.poll()? {
+                if self.state.is_active {
+                    self.state.is_active = false;
+                    self.state.status = PlayStatus::kPlayStatusStop;
+                    self.player.stop();
+
+                    self.notify(None);
+                    self.emit(SpircEvent::Inactive);
+                    self.emit(SpircEvent::Stopped);
+                }
+
+                self.reset_idle_timer();
+                progress = true;
+            }
+
             if !progress {
                 return Ok(Async::NotReady);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dummy_track() -> SpotifyId {
+        SpotifyId::from_raw(&[0u8; 16])
+    }
+
+    fn state_with(tracks_len: u32, index: u32, shuffle: bool, repeat: bool, shuffle_order: Vec<u32>) -> SpircState {
+        let mut state = SpircState::new("test".to_owned());
+        state.tracks = (0..tracks_len).map(|_| dummy_track()).collect();
+        state.index = index;
+        state.shuffle = shuffle;
+        state.repeat = repeat;
+        state.shuffle_order = shuffle_order;
+        state
+    }
+
+    #[test]
+    fn next_index_boundary_matrix() {
+        // (tracks_len, index, shuffle, repeat, shuffle_order, expected)
+        let cases: Vec<(u32, u32, bool, bool, Vec<u32>, Option<u32>)> = vec![
+            (0, 0, false, false, vec![], None),
+            (0, 0, false, true, vec![], None),
+            // single track: no repeat stops, repeat reloads itself
+            (1, 0, false, false, vec![0], None),
+            (1, 0, false, true, vec![0], Some(0)),
+            // linear order, mid-list always advances regardless of repeat
+            (3, 0, false, false, vec![0, 1, 2], Some(1)),
+            (3, 0, false, true, vec![0, 1, 2], Some(1)),
+            // linear order, end of list: stop without repeat, wrap with
+            (3, 2, false, false, vec![0, 1, 2], None),
+            (3, 2, false, true, vec![0, 1, 2], Some(0)),
+            // shuffled order, mid-permutation advances to the next slot
+            (3, 1, true, false, vec![2, 1, 0], Some(0)),
+            // shuffled order, end of permutation: stop without repeat, wrap with
+            (3, 0, true, false, vec![2, 1, 0], None),
+            (3, 0, true, true, vec![2, 1, 0], Some(2)),
+        ];
+
+        for (len, index, shuffle, repeat, order, expected) in cases {
+            let state = state_with(len, index, shuffle, repeat, order);
+            assert_eq!(state.next_index(), expected,
+                       "len={} index={} shuffle={} repeat={}", len, index, shuffle, repeat);
+        }
+    }
+
+    #[test]
+    fn prev_index_boundary_matrix() {
+        // (tracks_len, index, shuffle, repeat, shuffle_order, expected)
+        let cases: Vec<(u32, u32, bool, bool, Vec<u32>, Option<u32>)> = vec![
+            (0, 0, false, false, vec![], None),
+            (0, 0, false, true, vec![], None),
+            // single track: no repeat stops, repeat reloads itself
+            (1, 0, false, false, vec![0], None),
+            (1, 0, false, true, vec![0], Some(0)),
+            // linear order, mid-list always steps back regardless of repeat
+            (3, 2, false, false, vec![0, 1, 2], Some(1)),
+            (3, 2, false, true, vec![0, 1, 2], Some(1)),
+            // linear order, start of list: stop without repeat, wrap with
+            (3, 0, false, false, vec![0, 1, 2], None),
+            (3, 0, false, true, vec![0, 1, 2], Some(2)),
+            // shuffled order, mid-permutation steps back to the prior slot
+            (3, 1, true, false, vec![2, 1, 0], Some(2)),
+            // shuffled order, start of permutation: stop without repeat, wrap with
+            (3, 2, true, false, vec![2, 1, 0], None),
+            (3, 2, true, true, vec![2, 1, 0], Some(0)),
+        ];
+
+        for (len, index, shuffle, repeat, order, expected) in cases {
+            let state = state_with(len, index, shuffle, repeat, order);
+            assert_eq!(state.prev_index(), expected,
+                       "len={} index={} shuffle={} repeat={}", len, index, shuffle, repeat);
+        }
+    }
+
+    #[test]
+    fn reshuffle_disabled_is_identity_order() {
+        let mut state = state_with(4, 0, false, false, vec![]);
+        state.reshuffle();
+        assert_eq!(state.shuffle_order, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn reshuffle_enabled_is_a_permutation() {
+        let mut state = state_with(5, 0, true, false, vec![]);
+        state.reshuffle();
+
+        let mut sorted = state.shuffle_order.clone();
+        sorted.sort();
+        assert_eq!(sorted, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn reshuffle_empty_list_is_empty() {
+        let mut state = state_with(0, 0, true, false, vec![]);
+        state.reshuffle();
+        assert!(state.shuffle_order.is_empty());
+    }
+
+    #[test]
+    fn to_gain_linear() {
+        assert_eq!(VolumeCtrl::Linear.to_gain(0), 0.0);
+        assert!((VolumeCtrl::Linear.to_gain(0x7FFF) - 0.5).abs() < 1e-3);
+        assert_eq!(VolumeCtrl::Linear.to_gain(0xFFFF), 1.0);
+    }
+
+    #[test]
+    fn to_gain_logarithmic() {
+        // v=0 is silence, v=1 is unity, and the curve is well below
+        // linear at the midpoint.
+        assert_eq!(VolumeCtrl::Logarithmic.to_gain(0), 0.0);
+
+        let mid = VolumeCtrl::Logarithmic.to_gain(0x7FFF);
+        assert!(mid > 0.0 && mid < 0.5);
+
+        assert!((VolumeCtrl::Logarithmic.to_gain(0xFFFF) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn to_gain_fixed_is_always_unity() {
+        for &v in &[0u16, 0x7FFF, 0xFFFF] {
+            assert_eq!(VolumeCtrl::Fixed.to_gain(v), 1.0);
+        }
+    }
+}